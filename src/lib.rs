@@ -4,16 +4,21 @@
 //!
 //! This is a derivative work of
 //! [`figment::Jail`](https://docs.rs/figment/latest/figment/struct.Jail.html)
-//! but simpler (no environment variables), and it supports async closures.
+//! but simpler, and it supports async closures.
 
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{BufWriter, Write as _};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 
 use tempfile::TempDir;
 use thiserror::Error;
 
+#[cfg(unix)]
+use rustix::fd::OwnedFd;
+
 /// The result type used by [`LitterTray`]
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -30,6 +35,41 @@ pub enum Error {
     Uncontained(PathBuf),
 }
 
+/// A single fixture entry, used with [`LitterTray::populate`] to declaratively
+/// build up a tree of files, directories and (on Unix) symlinks in one call.
+#[derive(Debug, Clone, Copy)]
+pub enum Entry<'a> {
+    /// A directory.
+    Dir,
+    /// A file with the given contents.
+    File(&'a [u8]),
+    /// A symbolic link to the given target.
+    ///
+    /// *This variant is only available on Unix platforms.*
+    #[cfg(unix)]
+    Symlink(&'a Path),
+}
+
+/// The kind of filesystem entry produced by [`LitterTray::walk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+    /// A directory.
+    Dir,
+    /// A regular file.
+    File,
+    /// A symbolic link.
+    Symlink,
+}
+
+/// One entry produced by a depth-first traversal of the sandbox; see [`LitterTray::walk`].
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// This entry's path, relative to the tray root.
+    pub path: PathBuf,
+    /// The kind of this entry.
+    pub kind: EntryKind,
+}
+
 /// Lightweight filesystem sandbox
 ///
 /// This is little more than a convenience wrapper to
@@ -42,7 +82,8 @@ pub enum Error {
 /// On drop, the temporary directory is automatically cleaned up.
 ///
 /// <div class="warning">
-/// While this crate contains no <i>unsafe</i> Rust, it is not without limitation.
+/// This crate uses a small amount of <i>unsafe</i> Rust to mutate process environment
+/// variables (see [`LitterTray::set_env`]); it is not without limitation.
 /// <tt>LitterTray</tt> uses a global lock to prevent tests from conflicting when run in parallel
 /// (which is cargo's default behaviour).
 /// This has the effect of serialising your tests.
@@ -55,6 +96,16 @@ pub struct LitterTray {
     canonical_dir: PathBuf,
     _dir: TempDir,
     saved_cwd: PathBuf,
+    /// Prior value (if any) of each environment variable touched via
+    /// [`LitterTray::set_env`] or [`LitterTray::remove_env`], keyed by name.
+    /// `None` means the variable was not present; stored as `OsString` (rather
+    /// than `String`) so that non-Unicode values round-trip correctly.
+    saved_env: HashMap<String, Option<std::ffi::OsString>>,
+    /// Open directory handle onto the tray root, used for race-free, cwd-independent
+    /// creation by trays started with [`LitterTray::try_with_concurrent`].
+    /// `None` for trays started with [`LitterTray::try_with`] or [`LitterTray::try_with_async`].
+    #[cfg(unix)]
+    root_fd: Option<OwnedFd>,
 }
 
 /// This mutex ensures that only one test can use a litter tray at once.
@@ -62,6 +113,14 @@ pub struct LitterTray {
 /// If you want to parallelise testing, consider [`rusty_fork`](https://docs.rs/rusty-fork/latest/rusty_fork/).
 static G_LOCK: Mutex<()> = Mutex::new(());
 
+/// Serialises reads and writes of the process environment made through
+/// [`LitterTray::set_env`], [`LitterTray::remove_env`] and [`LitterTray::env`].
+///
+/// This is a separate lock from [`G_LOCK`] because [`LitterTray::try_with_concurrent`]
+/// deliberately does not take `G_LOCK`, so without a dedicated lock, two threads
+/// each driving a concurrent tray could race on `std::env::set_var`/`remove_var`.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
 impl LitterTray {
     /// Runs a closure in a new sandbox, passing the sandbox to the closure.
     ///
@@ -90,6 +149,9 @@ impl LitterTray {
             canonical_dir: dir.path().canonicalize()?,
             _dir: dir,
             saved_cwd: std::env::current_dir()?,
+            saved_env: HashMap::new(),
+            #[cfg(unix)]
+            root_fd: None,
         };
         std::env::set_current_dir(tray.directory())?;
         let outcome = f(&mut tray);
@@ -141,6 +203,9 @@ impl LitterTray {
             canonical_dir: dir.path().canonicalize()?,
             _dir: dir,
             saved_cwd: std::env::current_dir()?,
+            saved_env: HashMap::new(),
+            #[cfg(unix)]
+            root_fd: None,
         };
         std::env::set_current_dir(tray.directory())?;
         let outcome = f(&mut tray).await;
@@ -148,6 +213,74 @@ impl LitterTray {
         outcome
     }
 
+    /// Runs a closure in a new sandbox without taking the global lock or changing
+    /// the process's working directory, so that multiple sandboxes may be used
+    /// concurrently (e.g. from parallel test threads).
+    ///
+    /// Because the cwd is left untouched, closures must build paths with
+    /// [`LitterTray::resolve`] rather than bare relative paths; a bare relative
+    /// path given to anything else (such as `std::fs`) is resolved against
+    /// whatever the *real* current directory happens to be, not the sandbox.
+    /// On Unix, the sandbox root is opened once with `O_DIRECTORY` and the
+    /// creation methods perform all creation relative to that handle
+    /// (`openat`/`mkdirat`/`symlinkat`), so concurrent trays can't collide and a
+    /// bare relative path given to them lands in the sandbox. On other
+    /// platforms, a bare relative path given to them is used as it stands.
+    ///
+    /// # Returns
+    /// Whatever the closure returns.
+    ///
+    /// ```
+    /// use littertray::LitterTray;
+    ///
+    /// let result = LitterTray::try_with_concurrent(|tray| {
+    ///   let path = tray.resolve("test.txt")?;
+    ///   let _ = tray.create_text(&path, "Hello, world!")?;
+    ///   assert_eq!(std::fs::read_to_string(path)?, "Hello, world!");
+    ///   Ok(42)
+    /// }).unwrap();
+    /// ```
+    pub fn try_with_concurrent<R, F: FnOnce(&mut LitterTray) -> Result<R>>(f: F) -> Result<R> {
+        let dir = TempDir::new()?;
+        let canonical_dir = dir.path().canonicalize()?;
+        #[cfg(unix)]
+        let root_fd = Some(Self::open_root_fd(&canonical_dir)?);
+        let mut tray = LitterTray {
+            canonical_dir,
+            _dir: dir,
+            saved_cwd: std::env::current_dir()?,
+            saved_env: HashMap::new(),
+            #[cfg(unix)]
+            root_fd,
+        };
+        let outcome = f(&mut tray);
+        drop(tray);
+        outcome
+    }
+
+    #[cfg(unix)]
+    fn open_root_fd(path: &Path) -> Result<OwnedFd> {
+        use rustix::fs::{open, Mode, OFlags};
+        open(path, OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())
+            .map_err(std::io::Error::from)
+            .map_err(Error::from)
+    }
+
+    /// Resolves `path` to an absolute path within the sandbox, without relying on
+    /// the process's current working directory.
+    ///
+    /// This is the primary way to build paths for use inside a
+    /// [`LitterTray::try_with_concurrent`] closure. A relative `path` is joined
+    /// onto [`LitterTray::directory()`]; an absolute `path` is returned unchanged
+    /// provided it lies within the sandbox.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let path = dedot(path);
+        if path.is_absolute() {
+            return self.safe_path_within_tray(path);
+        }
+        self.safe_path_within_tray(self.canonical_dir.join(path))
+    }
+
     /// Returns the absolute path to the temporary directory that is this sandbox.
     /// This directory will be removed on drop.
     #[must_use]
@@ -155,6 +288,67 @@ impl LitterTray {
         &self.canonical_dir
     }
 
+    /// Sets an environment variable for the remainder of the sandbox's life.
+    ///
+    /// The variable's prior value (or absence) is recorded the first time it is
+    /// touched via [`LitterTray::set_env`] or [`LitterTray::remove_env`], and is
+    /// restored when the tray is dropped.
+    ///
+    /// This is safe to call from any [`LitterTray`], including one built by
+    /// [`LitterTray::try_with_concurrent`]: the mutation is serialised by a
+    /// dedicated lock, independently of the lock [`LitterTray::try_with`] uses
+    /// to serialise tray use.
+    ///
+    /// ```
+    /// use littertray::LitterTray;
+    ///
+    /// LitterTray::run(|tray| {
+    ///   tray.set_env("LITTERTRAY_TEST_VAR", "hello");
+    ///   assert_eq!(std::env::var("LITTERTRAY_TEST_VAR").unwrap(), "hello");
+    /// });
+    /// ```
+    pub fn set_env<K: AsRef<str>, V: AsRef<str>>(&mut self, key: K, value: V) {
+        let _guard = ENV_LOCK.lock().unwrap();
+        self.remember_env(key.as_ref());
+        // SAFETY: ENV_LOCK is held for the duration of this read-modify-write,
+        // so no other thread using LitterTray can be reading or writing the
+        // environment concurrently.
+        unsafe {
+            std::env::set_var(key.as_ref(), value.as_ref());
+        }
+    }
+
+    /// Removes an environment variable for the remainder of the sandbox's life.
+    ///
+    /// See [`LitterTray::set_env`] for the restore-on-drop behaviour and safety notes.
+    pub fn remove_env<K: AsRef<str>>(&mut self, key: K) {
+        let _guard = ENV_LOCK.lock().unwrap();
+        self.remember_env(key.as_ref());
+        // SAFETY: see LitterTray::set_env.
+        unsafe {
+            std::env::remove_var(key.as_ref());
+        }
+    }
+
+    /// Returns the current value of an environment variable, if it is set and
+    /// contains valid Unicode. This is a convenience wrapper for [`std::env::var`].
+    #[must_use]
+    pub fn env<K: AsRef<str>>(&self, key: K) -> Option<String> {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::var(key.as_ref()).ok()
+    }
+
+    /// Records the prior value of `key`, if this is the first time it has been touched.
+    ///
+    /// Uses [`std::env::var_os`] rather than [`std::env::var`] so that a variable
+    /// holding non-Unicode data is recorded (and later restored) faithfully,
+    /// rather than being conflated with "not present".
+    fn remember_env(&mut self, key: &str) {
+        self.saved_env
+            .entry(key.to_string())
+            .or_insert_with(|| std::env::var_os(key));
+    }
+
     fn safe_path_within_tray<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
         let path = dedot(path);
         if path.is_absolute() {
@@ -172,7 +366,7 @@ impl LitterTray {
     /// or an absolute path within the sandbox (see [`LitterTray::directory()`]).
     pub fn create_binary<P: AsRef<Path>>(&self, path: P, bytes: &[u8]) -> Result<File> {
         let path = self.safe_path_within_tray(path)?;
-        let file = File::create(path)?;
+        let file = self.open_for_write(&path)?;
         let mut writer = BufWriter::new(file);
         writer.write_all(bytes)?;
         Ok(writer
@@ -180,6 +374,26 @@ impl LitterTray {
             .map_err(std::io::IntoInnerError::into_error)?)
     }
 
+    /// Opens `path` for writing (create, truncate), relative to the open tray
+    /// root fd when this tray is in [`LitterTray::try_with_concurrent`] mode,
+    /// otherwise via the plain path.
+    fn open_for_write(&self, path: &Path) -> Result<File> {
+        #[cfg(unix)]
+        if let Some(fd) = &self.root_fd {
+            use rustix::fs::{openat, Mode, OFlags};
+            let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(path);
+            let raw = openat(
+                fd,
+                rel,
+                OFlags::CREATE | OFlags::WRONLY | OFlags::TRUNC | OFlags::CLOEXEC,
+                Mode::from_raw_mode(0o666),
+            )
+            .map_err(std::io::Error::from)?;
+            return Ok(File::from(raw));
+        }
+        Ok(File::create(path)?)
+    }
+
     /// Creates a text file within the sandbox from the provided contents.
     ///
     /// The given path must either be a relative filename,
@@ -188,6 +402,66 @@ impl LitterTray {
         self.create_binary(path, contents.as_bytes())
     }
 
+    /// Creates a binary file within the sandbox, as [`LitterTray::create_binary`],
+    /// but so that the file is never observed in a partially-written state.
+    ///
+    /// The contents are written in full to a temporary sibling file (so that the
+    /// final `rename` stays on one filesystem) which is then renamed over the
+    /// destination in a single atomic call. If the parent directory does not yet
+    /// exist, it is created and the write is retried once.
+    ///
+    /// The given path must either be a relative filename,
+    /// or an absolute path within the sandbox (see [`LitterTray::directory()`]).
+    pub fn create_binary_atomic<P: AsRef<Path>>(&self, path: P, bytes: &[u8]) -> Result<File> {
+        let path = self.safe_path_within_tray(path)?;
+        match self.write_atomic(&path, bytes) {
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    self.create_dir_all(parent)?;
+                }
+                self.write_atomic(&path, bytes)
+            }
+            other => other,
+        }
+    }
+
+    /// Creates a text file within the sandbox, as [`LitterTray::create_text`],
+    /// but so that the file is never observed in a partially-written state.
+    ///
+    /// See [`LitterTray::create_binary_atomic`] for the atomicity guarantee.
+    pub fn create_text_atomic<P: AsRef<Path>>(&self, path: P, contents: &str) -> Result<File> {
+        self.create_binary_atomic(path, contents.as_bytes())
+    }
+
+    /// Writes `bytes` to a temporary file alongside `path`, then renames it into place.
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<File> {
+        let tmp_path = tmp_sibling_path(path);
+        let file = self.open_for_write(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        let file = writer
+            .into_inner()
+            .map_err(std::io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        self.rename(&tmp_path, path)?;
+        Ok(file)
+    }
+
+    /// Renames `from` to `to`, relative to the open tray root fd when this tray
+    /// is in [`LitterTray::try_with_concurrent`] mode, otherwise via the plain paths.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(fd) = &self.root_fd {
+            let from_rel = from.strip_prefix(&self.canonical_dir).unwrap_or(from);
+            let to_rel = to.strip_prefix(&self.canonical_dir).unwrap_or(to);
+            rustix::fs::renameat(fd, from_rel, fd, to_rel).map_err(std::io::Error::from)?;
+            return Ok(());
+        }
+        fs::rename(from, to)?;
+        Ok(())
+    }
+
     /// Creates a directory within the sandbox.
     ///
     /// The given path must either be a relative filename,
@@ -203,10 +477,168 @@ impl LitterTray {
     /// ```
     pub fn make_dir<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
         let path = self.safe_path_within_tray(path)?;
-        fs::create_dir_all(&path)?;
+        self.create_dir_all(&path)?;
         Ok(path)
     }
 
+    /// Creates `path`, and any missing parents, relative to the open tray root
+    /// fd when this tray is in [`LitterTray::try_with_concurrent`] mode,
+    /// otherwise via the plain path.
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(fd) = &self.root_fd {
+            let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(path);
+            return Self::mkdirat_all(fd, rel, 0o777);
+        }
+        fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn mkdirat_all(fd: &OwnedFd, rel: &Path, mode: u32) -> Result<()> {
+        use rustix::fs::{mkdirat, Mode};
+        let mut built = PathBuf::new();
+        for component in rel.components() {
+            built.push(component);
+            match mkdirat(fd, &built, Mode::from_raw_mode(mode)) {
+                Ok(()) | Err(rustix::io::Errno::EXIST) => {}
+                Err(err) => return Err(Error::Io(err.into())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Declaratively populates the sandbox with a fixture tree in one call,
+    /// creating intermediate directories as needed.
+    ///
+    /// Every path is validated with the same containment check used by the
+    /// other `LitterTray` methods, so a fixture cannot escape the sandbox.
+    ///
+    /// ```
+    /// use littertray::{Entry, LitterTray};
+    ///
+    /// LitterTray::run(|tray| {
+    ///   tray.populate(&[
+    ///     ("config", Entry::Dir),
+    ///     ("config/app.toml", Entry::File(b"[section]\n")),
+    ///   ]).unwrap();
+    ///   assert!(std::fs::exists("config/app.toml").unwrap());
+    /// });
+    /// ```
+    pub fn populate<P: AsRef<Path>>(&self, entries: &[(P, Entry<'_>)]) -> Result<()> {
+        for (path, entry) in entries {
+            let path = self.safe_path_within_tray(path)?;
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    self.create_dir_all(parent)?;
+                }
+            }
+            match entry {
+                Entry::Dir => {
+                    self.create_dir_all(&path)?;
+                }
+                Entry::File(bytes) => {
+                    let file = self.open_for_write(&path)?;
+                    let mut writer = BufWriter::new(file);
+                    writer.write_all(bytes)?;
+                }
+                #[cfg(unix)]
+                Entry::Symlink(target) => {
+                    let target = self.safe_path_within_tray(target)?;
+                    if let Some(fd) = &self.root_fd {
+                        let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(&path);
+                        rustix::fs::symlinkat(&target, fd, rel)
+                            .map_err(std::io::Error::from)?;
+                    } else {
+                        std::os::unix::fs::symlink(&target, &path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Recursively copies a directory tree from the host filesystem into the
+    /// sandbox, under `dest_rel`.
+    ///
+    /// Subdirectories are recreated, file contents are copied, and symlinks are
+    /// reproduced as symlinks rather than dereferenced (on Unix; on other
+    /// platforms a symlink is copied with [`fs::copy`], which copies what it
+    /// points to). This is a convenient way to snapshot a checked-in fixture
+    /// directory into the sandbox.
+    ///
+    /// The destination must either be a relative path, or an absolute path
+    /// within the sandbox (see [`LitterTray::directory()`]).
+    pub fn copy_tree_from<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        src: P,
+        dest_rel: Q,
+    ) -> Result<()> {
+        let dest = self.safe_path_within_tray(dest_rel)?;
+        self.copy_tree_inner(src.as_ref(), &dest)
+    }
+
+    fn copy_tree_inner(&self, src: &Path, dest: &Path) -> Result<()> {
+        let file_type = fs::symlink_metadata(src)?.file_type();
+        if file_type.is_dir() {
+            self.create_dir_all(dest)?;
+            for entry in fs::read_dir(src)? {
+                let entry = entry?;
+                self.copy_tree_inner(&entry.path(), &dest.join(entry.file_name()))?;
+            }
+        } else if file_type.is_symlink() {
+            self.copy_symlink(src, dest)?;
+        } else {
+            self.copy_file(src, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the regular file `src` to `dest`, relative to the open tray root
+    /// fd when this tray is in [`LitterTray::try_with_concurrent`] mode,
+    /// otherwise via the plain path (which also preserves `src`'s permissions).
+    fn copy_file(&self, src: &Path, dest: &Path) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(fd) = &self.root_fd {
+            use rustix::fs::{chmodat, AtFlags, Mode};
+            use std::os::unix::fs::PermissionsExt as _;
+            let bytes = fs::read(src)?;
+            let mode = fs::metadata(src)?.permissions().mode();
+            let file = self.open_for_write(dest)?;
+            let mut writer = BufWriter::new(file);
+            writer.write_all(&bytes)?;
+            drop(writer);
+            // `open_for_write`'s mode is umask-masked; chmod afterwards so the
+            // destination's permissions match `src` exactly, the same way
+            // `fs::copy` does on the non-concurrent path below.
+            let rel = dest.strip_prefix(&self.canonical_dir).unwrap_or(dest);
+            chmodat(fd, rel, Mode::from_raw_mode(mode), AtFlags::empty())
+                .map_err(std::io::Error::from)?;
+            return Ok(());
+        }
+        let _ = fs::copy(src, dest)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn copy_symlink(&self, src: &Path, dest: &Path) -> Result<()> {
+        let target = fs::read_link(src)?;
+        if let Some(fd) = &self.root_fd {
+            let rel = dest.strip_prefix(&self.canonical_dir).unwrap_or(dest);
+            rustix::fs::symlinkat(&target, fd, rel).map_err(std::io::Error::from)?;
+        } else {
+            std::os::unix::fs::symlink(target, dest)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn copy_symlink(&self, src: &Path, dest: &Path) -> Result<()> {
+        let _ = fs::copy(src, dest)?;
+        Ok(())
+    }
+
+
     #[cfg(unix)]
     /// Creates a symbolic link within the sandbox.
     /// Returns the path to the new symlink.
@@ -219,20 +651,265 @@ impl LitterTray {
     ) -> Result<PathBuf> {
         let path_orig = self.safe_path_within_tray(original)?;
         let path_link = self.safe_path_within_tray(link)?;
-        std::os::unix::fs::symlink(path_orig, &path_link)?;
+        if let Some(fd) = &self.root_fd {
+            let rel = path_link
+                .strip_prefix(&self.canonical_dir)
+                .unwrap_or(&path_link);
+            rustix::fs::symlinkat(&path_orig, fd, rel).map_err(std::io::Error::from)?;
+        } else {
+            std::os::unix::fs::symlink(path_orig, &path_link)?;
+        }
         Ok(path_link)
     }
+
+    #[cfg(unix)]
+    /// Creates a binary file within the sandbox from the provided contents,
+    /// with the given Unix permission mode (e.g. `0o600`).
+    ///
+    /// *This method is only available on Unix platforms.*
+    pub fn create_binary_with_mode<P: AsRef<Path>>(
+        &self,
+        path: P,
+        bytes: &[u8],
+        mode: u32,
+    ) -> Result<File> {
+        let path = self.safe_path_within_tray(path)?;
+        let file = self.open_for_write_with_mode(&path, mode)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(bytes)?;
+        Ok(writer
+            .into_inner()
+            .map_err(std::io::IntoInnerError::into_error)?)
+    }
+
+    /// As [`LitterTray::open_for_write`], but creates the file with the given
+    /// Unix permission mode rather than the default `0o666`.
+    #[cfg(unix)]
+    fn open_for_write_with_mode(&self, path: &Path, mode: u32) -> Result<File> {
+        if let Some(fd) = &self.root_fd {
+            use rustix::fs::{openat, Mode, OFlags};
+            let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(path);
+            let raw = openat(
+                fd,
+                rel,
+                OFlags::CREATE | OFlags::WRONLY | OFlags::TRUNC | OFlags::CLOEXEC,
+                Mode::from_raw_mode(mode),
+            )
+            .map_err(std::io::Error::from)?;
+            return Ok(File::from(raw));
+        }
+        use std::os::unix::fs::OpenOptionsExt as _;
+        Ok(fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(path)?)
+    }
+
+    #[cfg(unix)]
+    /// Creates a directory within the sandbox with the given Unix permission mode
+    /// (e.g. `0o700`).
+    ///
+    /// *This method is only available on Unix platforms.*
+    pub fn make_dir_with_mode<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<PathBuf> {
+        let path = self.safe_path_within_tray(path)?;
+        if let Some(fd) = &self.root_fd {
+            let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(&path);
+            Self::mkdirat_all(fd, rel, mode)?;
+        } else {
+            use std::os::unix::fs::DirBuilderExt as _;
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(mode)
+                .create(&path)?;
+        }
+        Ok(path)
+    }
+
+    #[cfg(unix)]
+    /// Sets the Unix permission mode of an existing file or directory within the sandbox.
+    ///
+    /// *This method is only available on Unix platforms.*
+    pub fn set_permissions<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let path = self.safe_path_within_tray(path)?;
+        if let Some(fd) = &self.root_fd {
+            use rustix::fs::{chmodat, AtFlags, Mode};
+            let rel = path.strip_prefix(&self.canonical_dir).unwrap_or(&path);
+            chmodat(fd, rel, Mode::from_raw_mode(mode), AtFlags::empty())
+                .map_err(std::io::Error::from)?;
+        } else {
+            use std::os::unix::fs::PermissionsExt as _;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Walks the sandbox depth-first, returning every entry (directories, files
+    /// and symlinks) below the tray root.
+    ///
+    /// Each entry's path is relative to [`LitterTray::directory()`], so
+    /// assertions on the result are stable across runs.
+    pub fn walk(&self) -> Result<Vec<WalkEntry>> {
+        let mut out = Vec::new();
+        Self::walk_inner(&self.canonical_dir, &self.canonical_dir, &mut out)?;
+        Ok(out)
+    }
+
+    fn walk_inner(root: &Path, dir: &Path, out: &mut Vec<WalkEntry>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let relative_path = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            let file_type = entry.file_type()?;
+            let kind = if file_type.is_symlink() {
+                EntryKind::Symlink
+            } else if file_type.is_dir() {
+                EntryKind::Dir
+            } else {
+                EntryKind::File
+            };
+            let is_dir = kind == EntryKind::Dir;
+            out.push(WalkEntry {
+                path: relative_path,
+                kind,
+            });
+            if is_dir {
+                Self::walk_inner(root, &path, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches [`LitterTray::walk`]'s output against a shell-style glob pattern,
+    /// returning the matching paths, relative to the tray root.
+    ///
+    /// Supports `*` (any run of characters within one path component), `?` (any
+    /// single character), `**` (any number of path components, including zero)
+    /// and `[...]` character classes. Since the match only ever considers paths
+    /// produced by [`LitterTray::walk`], it can never escape the sandbox.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
+        let pattern_components: Vec<&str> =
+            pattern.split('/').filter(|c| !c.is_empty()).collect();
+        Ok(self
+            .walk()?
+            .into_iter()
+            .filter(|entry| {
+                let path_components: Vec<&str> = entry
+                    .path
+                    .components()
+                    .map(|c| c.as_os_str().to_str().unwrap_or(""))
+                    .collect();
+                glob_match_path(&pattern_components, &path_components)
+            })
+            .map(|entry| entry.path)
+            .collect())
+    }
 }
 
 impl Drop for LitterTray {
     /// On drop, `LitterTray`:
     /// - Changes the process's working directory to whatever it was on entry
+    /// - Restores every environment variable touched via [`LitterTray::set_env`]
+    ///   or [`LitterTray::remove_env`] to its original state
     /// - Cleans up the sandbox directory
     fn drop(&mut self) {
         let _ = std::env::set_current_dir(&self.saved_cwd);
+        if !self.saved_env.is_empty() {
+            let _guard = ENV_LOCK.lock().unwrap();
+            for (key, value) in &self.saved_env {
+                // SAFETY: see LitterTray::set_env.
+                unsafe {
+                    match value {
+                        Some(value) => std::env::set_var(key, value),
+                        None => std::env::remove_var(key),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Counter used to keep atomic-write temp file names unique within a process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a sibling path suitable for staging an atomic write to `path`,
+/// of the form `<name>.tmp.<pid>.<counter>`.
+fn tmp_sibling_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!("{name}.tmp.{}.{n}", std::process::id()))
+}
+
+/// Matches a sequence of glob pattern path components against a sequence of
+/// path components, where a `**` component matches any number of path
+/// components (including zero).
+fn glob_match_path(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(&"**") => {
+            glob_match_path(&pattern[1..], path)
+                || (!path.is_empty() && glob_match_path(pattern, &path[1..]))
+        }
+        Some(&component) => match path.first() {
+            Some(&first) if glob_match_component(component, first) => {
+                glob_match_path(&pattern[1..], &path[1..])
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Matches a single glob pattern (`*`, `?`, `[...]`) against a single path component.
+fn glob_match_component(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_match_chars(&pattern[1..], text)
+                || (!text.is_empty() && glob_match_chars(pattern, &text[1..]))
+        }
+        (Some('?'), Some(_)) => glob_match_chars(&pattern[1..], &text[1..]),
+        (Some('['), Some(&c)) => match pattern.iter().position(|&ch| ch == ']') {
+            Some(end) if glob_match_class(&pattern[1..end], c) => {
+                glob_match_chars(&pattern[end + 1..], &text[1..])
+            }
+            _ => false,
+        },
+        (Some(&p), Some(&t)) if p == t => glob_match_chars(&pattern[1..], &text[1..]),
+        _ => false,
     }
 }
 
+/// Matches `c` against a `[...]` character class body (already stripped of its brackets).
+fn glob_match_class(class: &[char], c: char) -> bool {
+    let (negate, class) = match class.first() {
+        Some('!' | '^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            if (class[i]..=class[i + 2]).contains(&c) {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    matched != negate
+}
+
 /// Remove any dots from the path by popping components as needed.
 fn dedot<P: AsRef<Path>>(path: P) -> PathBuf {
     #[allow(clippy::enum_glob_use)]
@@ -301,6 +978,140 @@ mod test {
             assert_eq!(prev_dir, getcwd());
         }
 
+        #[test]
+        fn concurrent_does_not_change_cwd() {
+            let prev_dir = getcwd();
+            LitterTray::try_with_concurrent(|tray| {
+                let path = tray.resolve("hi")?;
+                let _ = tray.create_text(&path, "hi")?;
+                assert_eq!(fs::read_to_string(path)?, "hi");
+                assert_eq!(prev_dir, getcwd());
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(prev_dir, getcwd());
+        }
+
+        #[test]
+        fn concurrent_resolve_rejects_outside_paths() {
+            LitterTray::try_with_concurrent(|tray| {
+                let _ = tray.resolve("/not-a-litter-tray").unwrap_err();
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn concurrent_set_env_from_multiple_threads() {
+            // Each thread drives its own try_with_concurrent tray and mutates a
+            // variable unique to that thread; ENV_LOCK must keep the
+            // read-modify-write in set_env race-free even though no tray holds
+            // G_LOCK here.
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    std::thread::spawn(move || {
+                        let key = format!("LITTERTRAY_TEST_CONCURRENT_{i}");
+                        LitterTray::try_with_concurrent(|tray| {
+                            tray.set_env(&key, "hi");
+                            assert_eq!(tray.env(&key).as_deref(), Some("hi"));
+                            Ok(())
+                        })
+                        .unwrap();
+                        assert!(std::env::var(&key).is_err());
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        }
+
+        #[test]
+        fn concurrent_atomic_write_uses_resolved_path() {
+            LitterTray::try_with_concurrent(|tray| {
+                let path = tray.resolve("nested/dir/atomic.txt")?;
+                let _ = tray.create_text_atomic(&path, "hi")?;
+                assert_eq!(fs::read_to_string(path)?, "hi");
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn concurrent_copy_tree_from_uses_resolved_path() {
+            let host_dir = tempfile::tempdir().unwrap();
+            fs::create_dir_all(host_dir.path().join("sub")).unwrap();
+            fs::write(host_dir.path().join("sub/file.txt"), b"fixture").unwrap();
+            LitterTray::try_with_concurrent(|tray| {
+                let dest = tray.resolve("fixture")?;
+                tray.copy_tree_from(host_dir.path(), &dest)?;
+                assert_eq!(
+                    fs::read_to_string(dest.join("sub/file.txt"))?,
+                    "fixture"
+                );
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn concurrent_copy_tree_from_preserves_source_mode() {
+            use std::os::unix::fs::PermissionsExt as _;
+            let host_dir = tempfile::tempdir().unwrap();
+            let host_file = host_dir.path().join("secret.txt");
+            fs::write(&host_file, b"shh").unwrap();
+            fs::set_permissions(&host_file, fs::Permissions::from_mode(0o600)).unwrap();
+            LitterTray::try_with_concurrent(|tray| {
+                let dest = tray.resolve("fixture")?;
+                tray.copy_tree_from(host_dir.path(), &dest)?;
+                let mode = fs::metadata(dest.join("secret.txt"))?.permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn concurrent_mode_methods_use_resolved_path() {
+            use std::os::unix::fs::PermissionsExt as _;
+            LitterTray::try_with_concurrent(|tray| {
+                let secret = tray.resolve("secret")?;
+                let _ = tray.create_binary_with_mode(&secret, b"shh", 0o600)?;
+                assert_eq!(fs::metadata(&secret)?.permissions().mode() & 0o777, 0o600);
+
+                let private = tray.resolve("private")?;
+                let dir_path = tray.make_dir_with_mode(&private, 0o700)?;
+                assert_eq!(fs::metadata(&dir_path)?.permissions().mode() & 0o777, 0o700);
+
+                tray.set_permissions(&secret, 0o400)?;
+                assert_eq!(fs::metadata(&secret)?.permissions().mode() & 0o777, 0o400);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn concurrent_populate_uses_resolved_path() {
+            use super::Entry;
+            let real_cwd = std::env::current_dir().unwrap();
+            LitterTray::try_with_concurrent(|tray| {
+                tray.populate(&[
+                    ("target.txt", Entry::File(b"hi")),
+                    ("link.txt", Entry::Symlink(std::path::Path::new("target.txt"))),
+                ])?;
+                let link = tray.resolve("link.txt")?;
+                assert_eq!(fs::read_to_string(&link)?, "hi");
+                assert_eq!(fs::read_link(&link)?, std::path::Path::new("target.txt"));
+                Ok(())
+            })
+            .unwrap();
+            assert!(!real_cwd.join("link.txt").exists());
+            assert!(!real_cwd.join("target.txt").exists());
+        }
+
         #[test]
         fn absolute_path() {
             LitterTray::try_with(|tray| {
@@ -325,6 +1136,182 @@ mod test {
             .unwrap();
         }
 
+        #[test]
+        fn set_env_restored_on_drop() {
+            // SAFETY: this test does not run concurrently with anything else that
+            // reads or writes the environment (rusty_fork gives it its own process).
+            unsafe {
+                std::env::set_var("LITTERTRAY_TEST_SET", "outer");
+                std::env::remove_var("LITTERTRAY_TEST_REMOVE");
+            }
+            LitterTray::run(|tray| {
+                tray.set_env("LITTERTRAY_TEST_SET", "inner");
+                tray.set_env("LITTERTRAY_TEST_REMOVE", "now set");
+                assert_eq!(tray.env("LITTERTRAY_TEST_SET").as_deref(), Some("inner"));
+                tray.remove_env("LITTERTRAY_TEST_SET");
+                assert_eq!(tray.env("LITTERTRAY_TEST_SET"), None);
+            });
+            assert_eq!(
+                std::env::var("LITTERTRAY_TEST_SET").as_deref(),
+                Ok("outer")
+            );
+            assert!(std::env::var("LITTERTRAY_TEST_REMOVE").is_err());
+        }
+
+        #[test]
+        fn populate_creates_fixture_tree() {
+            use super::Entry;
+            LitterTray::try_with(|tray| {
+                tray.populate(&[
+                    ("config", Entry::Dir),
+                    ("config/app.toml", Entry::File(b"[section]\n")),
+                    ("top.txt", Entry::File(b"hi")),
+                ])?;
+                assert!(fs::exists("config")?);
+                assert_eq!(
+                    fs::read_to_string("config/app.toml")?,
+                    "[section]\n"
+                );
+                assert_eq!(fs::read_to_string("top.txt")?, "hi");
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn populate_creates_symlink() {
+            use super::Entry;
+            LitterTray::try_with(|tray| {
+                tray.populate(&[
+                    ("target.txt", Entry::File(b"hi")),
+                    ("link.txt", Entry::Symlink(std::path::Path::new("target.txt"))),
+                ])?;
+                assert_eq!(fs::read_to_string("link.txt")?, "hi");
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn populate_symlink_outside_tray_fails() {
+            use super::Entry;
+            LitterTray::try_with(|tray| {
+                let _ = tray
+                    .populate(&[(
+                        "evil_link",
+                        Entry::Symlink(std::path::Path::new("/etc/passwd")),
+                    )])
+                    .unwrap_err();
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn copy_tree_from_host_directory() {
+            let host_dir = tempfile::tempdir().unwrap();
+            fs::create_dir_all(host_dir.path().join("sub")).unwrap();
+            fs::write(host_dir.path().join("sub/file.txt"), b"fixture").unwrap();
+            LitterTray::try_with(|tray| {
+                tray.copy_tree_from(host_dir.path(), "fixture")?;
+                assert_eq!(
+                    fs::read_to_string("fixture/sub/file.txt")?,
+                    "fixture"
+                );
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn walk_lists_whole_tree() {
+            use super::{Entry, EntryKind};
+            use std::collections::HashSet;
+            LitterTray::try_with(|tray| {
+                tray.populate(&[
+                    ("dir", Entry::Dir),
+                    ("dir/file.txt", Entry::File(b"hi")),
+                    ("top.txt", Entry::File(b"hi")),
+                ])?;
+                let entries = tray.walk()?;
+                let found: HashSet<_> = entries
+                    .iter()
+                    .map(|e| (e.path.clone(), e.kind))
+                    .collect();
+                assert!(found.contains(&(PathBuf::from("dir"), EntryKind::Dir)));
+                assert!(found.contains(&(PathBuf::from("dir/file.txt"), EntryKind::File)));
+                assert!(found.contains(&(PathBuf::from("top.txt"), EntryKind::File)));
+                assert_eq!(entries.len(), 3);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn glob_matches_patterns() {
+            use super::Entry;
+            LitterTray::try_with(|tray| {
+                tray.populate(&[
+                    ("dir", Entry::Dir),
+                    ("dir/a.txt", Entry::File(b"hi")),
+                    ("dir/b.tmp", Entry::File(b"hi")),
+                    ("dir/sub", Entry::Dir),
+                    ("dir/sub/c.txt", Entry::File(b"hi")),
+                ])?;
+                let mut txts = tray.glob("**/*.txt")?;
+                txts.sort();
+                assert_eq!(
+                    txts,
+                    vec![
+                        PathBuf::from("dir/a.txt"),
+                        PathBuf::from("dir/sub/c.txt"),
+                    ]
+                );
+                assert!(tray.glob("**/*.tmp")?.contains(&PathBuf::from("dir/b.tmp")));
+                assert!(tray.glob("dir/?.txt")?.contains(&PathBuf::from("dir/a.txt")));
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn atomic_write_creates_file() {
+            LitterTray::try_with(|tray| {
+                let _ = tray.create_text_atomic("atomic.txt", "Hello, world!").unwrap();
+                assert_eq!(fs::read_to_string("atomic.txt").unwrap(), "Hello, world!");
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn atomic_write_creates_missing_parent() {
+            LitterTray::try_with(|tray| {
+                let _ = tray
+                    .create_text_atomic("nested/dir/atomic.txt", "hi")
+                    .unwrap();
+                assert_eq!(fs::read_to_string("nested/dir/atomic.txt").unwrap(), "hi");
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[test]
+        fn atomic_write_leaves_no_tmp_file() {
+            LitterTray::try_with(|tray| {
+                let _ = tray.create_binary_atomic("atomic.bin", b"data").unwrap();
+                let entries: Vec<_> = fs::read_dir(tray.directory())
+                    .unwrap()
+                    .map(|e| e.unwrap().file_name())
+                    .collect();
+                assert_eq!(entries, vec![std::ffi::OsString::from("atomic.bin")]);
+                Ok(())
+            })
+            .unwrap();
+        }
+
         #[cfg(unix)]
         #[test]
         fn symlinks_work() {
@@ -339,6 +1326,46 @@ mod test {
             })
             .unwrap();
         }
+
+        #[cfg(unix)]
+        #[test]
+        fn create_binary_with_mode_sets_permissions() {
+            use std::os::unix::fs::PermissionsExt as _;
+            LitterTray::try_with(|tray| {
+                let _ = tray.create_binary_with_mode("secret", b"shh", 0o600)?;
+                let perms = fs::metadata("secret")?.permissions();
+                assert_eq!(perms.mode() & 0o777, 0o600);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn make_dir_with_mode_sets_permissions() {
+            use std::os::unix::fs::PermissionsExt as _;
+            LitterTray::try_with(|tray| {
+                let path = tray.make_dir_with_mode("private", 0o700)?;
+                let perms = fs::metadata(path)?.permissions();
+                assert_eq!(perms.mode() & 0o777, 0o700);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn set_permissions_chmods_existing_entry() {
+            use std::os::unix::fs::PermissionsExt as _;
+            LitterTray::try_with(|tray| {
+                let _ = tray.create_text("file.txt", "hi")?;
+                tray.set_permissions("file.txt", 0o400)?;
+                let perms = fs::metadata("file.txt")?.permissions();
+                assert_eq!(perms.mode() & 0o777, 0o400);
+                Ok(())
+            })
+            .unwrap();
+        }
     }
 
     #[cfg(feature = "async")]